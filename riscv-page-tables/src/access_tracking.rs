@@ -0,0 +1,152 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Working-set estimation for guest VMs via periodic Accessed (A) bit sampling.
+//!
+//! Walking every leaf PTE of a large guest on every tick is too expensive to run often, so
+//! `AccessTracker` instead divides a guest's mapped GPA space into a small number of adjustable
+//! regions and samples a bounded number of leaves per region per tick, adapting the region
+//! boundaries over time so that they track where the guest is actually touching memory: a region
+//! whose score stays high gets split so the hot sub-range can be identified more precisely, and
+//! adjacent regions that both stay cold get merged back down so the region set doesn't grow
+//! without bound.
+
+use alloc::vec::Vec;
+
+use riscv_pages::GuestPageAddr;
+
+use crate::page_table::GuestStagePageTable;
+use crate::page_table::PlatformPageTable;
+use crate::tlb;
+
+/// Number of leaves sampled per region on each `sample_tick()`, regardless of the region's size.
+const SAMPLES_PER_REGION: u64 = 16;
+/// A region is split once its rolling score reaches this threshold.
+const HOT_SCORE: u32 = SAMPLES_PER_REGION as u32 * 3 / 4;
+/// Two adjacent regions are merged once both of their rolling scores drop to this or below.
+const COLD_SCORE: u32 = 0;
+/// Regions smaller than this aren't split further.
+const MIN_REGION_PAGES: u64 = SAMPLES_PER_REGION;
+
+/// A contiguous range of a guest's GPA space tracked as a single access-sampling unit.
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    base: GuestPageAddr,
+    num_pages: u64,
+    /// Exponentially-decayed count of accessed samples seen on recent ticks.
+    nr_accesses: u32,
+}
+
+/// Samples stage-2 Accessed bits for one guest's `PlatformPageTable` to estimate its working set.
+pub struct AccessTracker<'a, T: GuestStagePageTable> {
+    table: &'a PlatformPageTable<T>,
+    regions: Vec<Region>,
+}
+
+impl<'a, T: GuestStagePageTable> AccessTracker<'a, T> {
+    /// Creates a tracker over `num_pages` 4k pages of `table`'s GPA space starting at `base`,
+    /// initially covered by a single region.
+    pub fn new(table: &'a PlatformPageTable<T>, base: GuestPageAddr, num_pages: u64) -> Self {
+        Self {
+            table,
+            regions: alloc::vec![Region {
+                base,
+                num_pages,
+                nr_accesses: 0,
+            }],
+        }
+    }
+
+    /// Runs one sampling pass: for every region, walks up to `SAMPLES_PER_REGION` of its leaves,
+    /// counts how many have the Accessed bit set, atomically clears A on each sampled leaf, and
+    /// folds the count into that region's rolling score. Regions are then split or merged based
+    /// on the updated scores. Bumps `TlbVersion` and fences once at the end of the pass so a
+    /// stale TLB entry can't keep suppressing A-bit updates on the next tick.
+    pub fn sample_tick(&mut self) {
+        for region in &mut self.regions {
+            let stride = (region.num_pages / SAMPLES_PER_REGION).max(1);
+            let mut hits = 0u32;
+            let mut addr = region.base;
+            for _ in 0..SAMPLES_PER_REGION.min(region.num_pages) {
+                // `leaf_slot_with_span` may return a huge-page leaf that covers more than one 4k
+                // page of the region; advance by at least its span so the same leaf isn't
+                // re-sampled (and double-counted) on the next stride within this tick.
+                let step = if let Some((slot, span)) = self.table.leaf_slot_with_span(addr) {
+                    if slot.clear_accessed() {
+                        hits += 1;
+                    }
+                    span.max(stride)
+                } else {
+                    stride
+                };
+                addr = match addr.checked_add_pages(step) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+            // Exponential decay: halve the old score before folding in this tick's hits, so a
+            // region that goes quiet cools down instead of latching at its historical peak.
+            region.nr_accesses = region.nr_accesses / 2 + hits;
+        }
+        tlb::increment_global_version();
+        tlb::hfence_gvma(self.table.owner(), None);
+        self.rebalance();
+    }
+
+    fn rebalance(&mut self) {
+        let mut next = Vec::with_capacity(self.regions.len());
+        let mut iter = self.regions.drain(..).peekable();
+        while let Some(region) = iter.next() {
+            if region.nr_accesses >= HOT_SCORE && region.num_pages >= 2 * MIN_REGION_PAGES {
+                let half = region.num_pages / 2;
+                // Safe to unwrap: `half < region.num_pages`, so the split point is still inside
+                // the guest's mapped range that `region` was carved out of.
+                let mid = region.base.checked_add_pages(half).unwrap();
+                next.push(Region {
+                    base: region.base,
+                    num_pages: half,
+                    nr_accesses: region.nr_accesses,
+                });
+                next.push(Region {
+                    base: mid,
+                    num_pages: region.num_pages - half,
+                    nr_accesses: region.nr_accesses,
+                });
+            } else if region.nr_accesses <= COLD_SCORE
+                && iter
+                    .peek()
+                    .map(|next_region| next_region.nr_accesses <= COLD_SCORE)
+                    .unwrap_or(false)
+            {
+                let neighbor = iter.next().unwrap();
+                next.push(Region {
+                    base: region.base,
+                    num_pages: region.num_pages + neighbor.num_pages,
+                    nr_accesses: 0,
+                });
+            } else {
+                next.push(region);
+            }
+        }
+        self.regions = next;
+    }
+
+    /// Returns the GPA ranges (as `(base, num_pages)`) of regions whose rolling score is at or
+    /// above `HOT_SCORE` -- good candidates for promotion or for staying resident.
+    pub fn hot_regions(&self) -> impl Iterator<Item = (GuestPageAddr, u64)> + '_ {
+        self.regions
+            .iter()
+            .filter(|r| r.nr_accesses >= HOT_SCORE)
+            .map(|r| (r.base, r.num_pages))
+    }
+
+    /// Returns the GPA ranges (as `(base, num_pages)`) of regions whose rolling score is at
+    /// `COLD_SCORE` -- good candidates for swap-out or demotion.
+    pub fn cold_regions(&self) -> impl Iterator<Item = (GuestPageAddr, u64)> + '_ {
+        self.regions
+            .iter()
+            .filter(|r| r.nr_accesses <= COLD_SCORE)
+            .map(|r| (r.base, r.num_pages))
+    }
+}
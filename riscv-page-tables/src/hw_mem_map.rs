@@ -0,0 +1,153 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use riscv_pages::{RawAddr, SupervisorPhys};
+
+/// Errors that can occur when building or querying a `HwMemMap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The given region overlaps with one already added to the map.
+    OverlappingRegion,
+    /// The region's base address isn't aligned to the map's required alignment.
+    UnalignedRegion,
+    /// The map is already full.
+    OutOfSpace,
+}
+
+/// Holds the result of an `HwMemMap` operation.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The maximum number of regions a `HwMemMap` can hold.
+const MAX_REGIONS: usize = 64;
+
+/// The type of memory contained in a `HwMemRegion`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HwMemRegionType {
+    /// Memory that's available for the hypervisor to hand out.
+    Available,
+    /// Memory reserved by the platform (e.g. firmware, device tree) that must not be touched.
+    Reserved(HwReservedMemType),
+}
+
+/// Sub-classification of `HwMemRegionType::Reserved` regions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HwReservedMemType {
+    /// Memory holding the flattened device tree passed in by firmware.
+    FirmwareReserved,
+    /// Memory used by the hypervisor's own image.
+    HypervisorImage,
+}
+
+/// A single contiguous region of physical memory.
+#[derive(Clone, Copy, Debug)]
+pub struct HwMemRegion {
+    base: RawAddr<SupervisorPhys>,
+    size: u64,
+    region_type: HwMemRegionType,
+}
+
+impl HwMemRegion {
+    /// Returns the base address of this region.
+    pub fn base(&self) -> RawAddr<SupervisorPhys> {
+        self.base
+    }
+
+    /// Returns the size of this region in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the type of this region.
+    pub fn region_type(&self) -> HwMemRegionType {
+        self.region_type
+    }
+}
+
+/// A map of the system's physical memory, built up from the regions reported by firmware.
+///
+/// Regions are kept sorted and non-overlapping, and are aligned to the alignment given to
+/// `HwMemMapBuilder::new()` so that the map can be consumed directly when building the host's
+/// top-level page table.
+pub struct HwMemMap {
+    regions: [Option<HwMemRegion>; MAX_REGIONS],
+    num_regions: usize,
+    align: u64,
+}
+
+impl HwMemMap {
+    /// Returns an iterator over the regions in this map, in address order.
+    pub fn regions(&self) -> impl Iterator<Item = &HwMemRegion> {
+        self.regions[..self.num_regions].iter().filter_map(|r| r.as_ref())
+    }
+
+    /// Returns the alignment this map was built with.
+    pub fn required_alignment(&self) -> u64 {
+        self.align
+    }
+}
+
+/// Builds a `HwMemMap` one region at a time.
+pub struct HwMemMapBuilder {
+    map: HwMemMap,
+}
+
+impl HwMemMapBuilder {
+    /// Creates a new, empty builder that will enforce `align`-aligned regions.
+    pub fn new(align: u64) -> Self {
+        Self {
+            map: HwMemMap {
+                regions: [None; MAX_REGIONS],
+                num_regions: 0,
+                align,
+            },
+        }
+    }
+
+    /// Adds a region of available memory starting at `base` and `size` bytes long.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `base` through `base + size` is valid, exclusively-owned
+    /// memory that isn't used by anything else at the time the built map is turned into a
+    /// `HypPageAlloc` by `HypPageAlloc::new()`.
+    pub unsafe fn add_memory_region(
+        mut self,
+        base: RawAddr<SupervisorPhys>,
+        size: u64,
+    ) -> Result<Self> {
+        if base.bits() as u64 % self.map.align != 0 {
+            return Err(Error::UnalignedRegion);
+        }
+        if self.map.num_regions >= MAX_REGIONS {
+            return Err(Error::OutOfSpace);
+        }
+        let new_base = base.bits() as u64;
+        let existing = &self.map.regions[..self.map.num_regions];
+        if existing.iter().flatten().any(|r| {
+            let r_base = r.base.bits() as u64;
+            new_base < r_base + r.size && r_base < new_base + size
+        }) {
+            return Err(Error::OverlappingRegion);
+        }
+        // Keep the regions sorted by base address.
+        let pos = existing
+            .iter()
+            .flatten()
+            .position(|r| r.base.bits() as u64 > new_base)
+            .unwrap_or(self.map.num_regions);
+        self.map.regions[pos..=self.map.num_regions].rotate_right(1);
+        self.map.regions[pos] = Some(HwMemRegion {
+            base,
+            size,
+            region_type: HwMemRegionType::Available,
+        });
+        self.map.num_regions += 1;
+        Ok(self)
+    }
+
+    /// Consumes the builder, returning the completed map.
+    pub fn build(self) -> HwMemMap {
+        self.map
+    }
+}
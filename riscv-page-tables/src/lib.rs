@@ -42,6 +42,8 @@
 
 extern crate alloc;
 
+/// Samples hardware Accessed bits to estimate a guest's working set.
+pub mod access_tracking;
 mod hw_mem_map;
 mod page_info;
 /// Implements a linked-list of pages using `PageTracker`.
@@ -58,6 +60,7 @@ pub mod sv48x4;
 /// Provides low-level TLB management functions such as fencing.
 pub mod tlb;
 
+pub use access_tracking::AccessTracker;
 pub use hw_mem_map::Error as MemMapError;
 pub use hw_mem_map::Result as MemMapResult;
 pub use hw_mem_map::{HwMemMap, HwMemMapBuilder, HwMemRegion, HwMemRegionType, HwReservedMemType};
@@ -90,6 +93,7 @@ mod tests {
     use super::sv48::Sv48;
     use super::sv48x4::Sv48x4;
     use super::*;
+    use crate::pte::{Pte, PteFieldBit};
 
     struct StubState {
         root_pages: SequentialPages<InternalClean>,
@@ -160,14 +164,14 @@ mod tests {
                 );
                 slice[0] = 0xdeadbeef;
             }
-            let mappable = page_tracker.assign_page_for_mapping(page, id).unwrap();
+            let mappable = guest_page_table.assign_page_for_mapping(page).unwrap();
             assert!(mapper.map_page(gpa, mappable).is_ok());
         }
         let version = TlbVersion::new();
         guest_page_table
             .invalidate_range::<Page<Invalidated>>(gpa_base, PageSize::Size4k, 2)
             .unwrap()
-            .for_each(|invalidated| page_tracker.convert_page(invalidated, version).unwrap());
+            .for_each(|invalidated| guest_page_table.convert_page(invalidated, version).unwrap());
         let version = version.increment();
         let mut converted_pages = guest_page_table
             .get_converted_range::<Page<ConvertedDirty>>(gpa_base, PageSize::Size4k, 2, version)
@@ -208,14 +212,14 @@ mod tests {
                 );
                 slice[0] = 0xdeadbeef;
             }
-            let mappable = page_tracker.assign_page_for_mapping(page, id).unwrap();
+            let mappable = guest_page_table.assign_page_for_mapping(page).unwrap();
             assert!(mapper.map_page(gpa, mappable).is_ok());
         }
         let version = TlbVersion::new();
         guest_page_table
             .invalidate_range::<Page<Invalidated>>(gpa_base, PageSize::Size4k, 2)
             .unwrap()
-            .for_each(|invalidated| page_tracker.convert_page(invalidated, version).unwrap());
+            .for_each(|invalidated| guest_page_table.convert_page(invalidated, version).unwrap());
         let version = version.increment();
         let mut converted_pages = guest_page_table
             .get_converted_range::<Page<ConvertedDirty>>(gpa_base, PageSize::Size4k, 2, version)
@@ -248,4 +252,200 @@ mod tests {
         let was_linked: Page<InternalClean> = unsafe { Page::new(first_page_addr) };
         new_list.push(was_linked).unwrap();
     }
+
+    #[test]
+    fn split_and_coalesce_round_trip() {
+        let state = stub_sys_memory();
+        let huge_addr = state.root_pages.base().checked_add_pages(512).unwrap();
+
+        let page_tracker = state.page_tracker;
+        let id = page_tracker.add_active_guest().unwrap();
+        let guest_page_table: PlatformPageTable<Sv48x4> =
+            PlatformPageTable::new(state.root_pages, id, page_tracker.clone())
+                .expect("creating sv48x4");
+
+        let mut pte_pages = state.pte_pages.into_iter();
+        let gpa_base = PageAddr::new(RawAddr::guest(0x8000_0000, PageOwnerId::host())).unwrap();
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size2Mb, 1, &mut || pte_pages.next())
+            .unwrap();
+        // Not safe -- just a test: directly wrap a physical range we know is free.
+        let huge_page: Page<InternalClean> = unsafe { Page::new(huge_addr) };
+        let huge_page = guest_page_table.assign_page_for_mapping(huge_page).unwrap();
+        mapper.map_page(gpa_base, huge_page).unwrap();
+
+        let (_, span) = guest_page_table.leaf_slot_with_span(gpa_base).unwrap();
+        assert_eq!(span, PageSize::Size2Mb as u64 / PageSize::Size4k as u64);
+        let original = guest_page_table.leaf_slot(gpa_base).unwrap().load();
+
+        guest_page_table
+            .split_range(gpa_base, PageSize::Size2Mb, 1, &mut || pte_pages.next())
+            .unwrap();
+        for gpa in gpa_base.iter_from().take(span as usize) {
+            let (_, child_span) = guest_page_table.leaf_slot_with_span(gpa).unwrap();
+            assert_eq!(child_span, 1);
+        }
+
+        guest_page_table
+            .coalesce_range(gpa_base, PageSize::Size2Mb, 1)
+            .unwrap();
+        let (_, restored_span) = guest_page_table.leaf_slot_with_span(gpa_base).unwrap();
+        assert_eq!(restored_span, span);
+        let restored = guest_page_table.leaf_slot(gpa_base).unwrap().load();
+        assert_eq!(restored.bits() & 0x3ff, original.bits() & 0x3ff);
+        assert_eq!(restored.pfn_addr(), original.pfn_addr());
+
+        // The child table built by `split_range` is released back to the `PageTracker` once
+        // `coalesce_range` collapses it away.
+        assert!(page_tracker.take_reclaimed_pte_pages().is_some());
+    }
+
+    #[test]
+    fn dirty_logging_collects_once_per_pass() {
+        let state = stub_sys_memory();
+        let page_addr = state.root_pages.base().checked_add_pages(512).unwrap();
+
+        let page_tracker = state.page_tracker;
+        let id = page_tracker.add_active_guest().unwrap();
+        let guest_page_table: PlatformPageTable<Sv48x4> =
+            PlatformPageTable::new(state.root_pages, id, page_tracker.clone())
+                .expect("creating sv48x4");
+
+        let mut pte_pages = state.pte_pages.into_iter();
+        let gpa = PageAddr::new(RawAddr::guest(0x8000_0000, PageOwnerId::host())).unwrap();
+        let mapper = guest_page_table
+            .map_range(gpa, PageSize::Size4k, 1, &mut || pte_pages.next())
+            .unwrap();
+        // Not safe -- just a test: directly wrap a physical page we know is free.
+        let page: Page<InternalClean> = unsafe { Page::new(page_addr) };
+        let page = guest_page_table.assign_page_for_mapping(page).unwrap();
+        mapper.map_page(gpa, page).unwrap();
+
+        guest_page_table
+            .enable_dirty_logging(gpa, PageSize::Size4k, 1, false)
+            .unwrap();
+
+        // There's no real hart in this test build to set D on a write, so fake one.
+        let slot = guest_page_table.leaf_slot(gpa).unwrap();
+        slot.store(Pte::new(slot.load().bits() | PteFieldBit::Dirty.mask()));
+
+        let dirty: Vec<GuestPageAddr> = guest_page_table
+            .collect_dirty(gpa, PageSize::Size4k, 1)
+            .unwrap()
+            .collect();
+        assert_eq!(dirty, [gpa]);
+        assert!(!guest_page_table.leaf_slot(gpa).unwrap().load().dirty());
+
+        // D was already cleared by the pass above, so a second pass finds nothing new.
+        let dirty_again: Vec<GuestPageAddr> = guest_page_table
+            .collect_dirty(gpa, PageSize::Size4k, 1)
+            .unwrap()
+            .collect();
+        assert!(dirty_again.is_empty());
+    }
+
+    #[test]
+    fn access_tracker_splits_hot_region_and_merges_cold_regions() {
+        let state = stub_sys_memory();
+        let base_addr = state.root_pages.base().checked_add_pages(512).unwrap();
+
+        let page_tracker = state.page_tracker;
+        let id = page_tracker.add_active_guest().unwrap();
+        let guest_page_table: PlatformPageTable<Sv48x4> =
+            PlatformPageTable::new(state.root_pages, id, page_tracker.clone())
+                .expect("creating sv48x4");
+
+        let mut pte_pages = state.pte_pages.into_iter();
+        let gpa_base = PageAddr::new(RawAddr::guest(0x8000_0000, PageOwnerId::host())).unwrap();
+        const NUM_PAGES: u64 = 32;
+        let mapper = guest_page_table
+            .map_range(gpa_base, PageSize::Size4k, NUM_PAGES, &mut || pte_pages.next())
+            .unwrap();
+        // Map and mark Accessed exactly the 16 addresses a 32-page region's `sample_tick` will
+        // sample (stride 2, i.e. every other page), so a single tick drives the whole region's
+        // score straight to `HOT_SCORE` and triggers a split.
+        for (i, gpa) in gpa_base.iter_from().take(NUM_PAGES as usize).enumerate() {
+            if i % 2 != 0 {
+                continue;
+            }
+            let page_addr = base_addr.checked_add_pages(i as u64).unwrap();
+            // Not safe -- just a test: directly wrap a physical page we know is free.
+            let page: Page<InternalClean> = unsafe { Page::new(page_addr) };
+            let page = guest_page_table.assign_page_for_mapping(page).unwrap();
+            mapper.map_page(gpa, page).unwrap();
+            let slot = guest_page_table.leaf_slot(gpa).unwrap();
+            slot.store(Pte::new(slot.load().bits() | PteFieldBit::Accessed.mask()));
+        }
+
+        let mut tracker = AccessTracker::new(&guest_page_table, gpa_base, NUM_PAGES);
+        tracker.sample_tick();
+        assert_eq!(tracker.hot_regions().count(), 2);
+
+        // Nothing re-sets Accessed after the first tick, so both halves' scores decay to 0 in
+        // lockstep and merge back together.
+        for _ in 0..5 {
+            tracker.sample_tick();
+        }
+        assert_eq!(tracker.cold_regions().count(), 1);
+    }
+
+    #[test]
+    fn share_then_resolve_cow_fault() {
+        let state = stub_sys_memory();
+        let shared_addr = state.root_pages.base().checked_add_pages(512).unwrap();
+        let copy_addr_1 = state.root_pages.base().checked_add_pages(513).unwrap();
+        let copy_addr_2 = state.root_pages.base().checked_add_pages(514).unwrap();
+
+        let page_tracker = state.page_tracker;
+        let owner_a = page_tracker.add_active_guest().unwrap();
+        let owner_b = page_tracker.add_active_guest().unwrap();
+        let owner_c = page_tracker.add_active_guest().unwrap();
+
+        // Not safe -- just a test: directly wrap a physical page we know is free.
+        let shared_page: Page<InternalClean> = unsafe { Page::new(shared_addr) };
+        let shared_page = page_tracker
+            .assign_page_for_mapping(shared_page, owner_a, false)
+            .unwrap();
+        unsafe {
+            // Not safe -- just a test.
+            *(shared_page.addr().bits() as *mut u64) = 0xc0ffee;
+        }
+
+        // With `MAX_PAGE_OWNERS` raised, a page can be shared with more than one other guest.
+        page_tracker.share_page_read_only(shared_addr, owner_b).unwrap();
+        page_tracker.share_page_read_only(shared_addr, owner_c).unwrap();
+
+        let mut copy_pages = [copy_addr_1, copy_addr_2].into_iter();
+        let new_page: Page<InternalClean> = page_tracker
+            .resolve_cow_fault(shared_addr, owner_b, &mut || {
+                // Not safe -- just a test.
+                copy_pages.next().map(|addr| unsafe { Page::new(addr) })
+            })
+            .unwrap();
+        assert_eq!(unsafe { *(new_page.addr().bits() as *const u64) }, 0xc0ffee);
+
+        // `owner_b` was just removed by the fault above, so resolving again on its behalf is no
+        // longer valid.
+        let retry = page_tracker
+            .resolve_cow_fault(shared_addr, owner_b, &mut || -> Option<Page<InternalClean>> {
+                None
+            });
+        assert!(retry.is_err());
+
+        // `owner_a` and `owner_c` are still sharing; resolving `owner_c`'s fault leaves only
+        // `owner_a`, so the original page reverts to being privately `Mapped`.
+        let _second_copy: Page<InternalClean> = page_tracker
+            .resolve_cow_fault(shared_addr, owner_c, &mut || {
+                // Not safe -- just a test.
+                copy_pages.next().map(|addr| unsafe { Page::new(addr) })
+            })
+            .unwrap();
+
+        // No sharers remain, so the page is no longer `Shared` and a further fault is rejected.
+        let result = page_tracker
+            .resolve_cow_fault(shared_addr, owner_a, &mut || -> Option<Page<InternalClean>> {
+                None
+            });
+        assert!(matches!(result, Err(PageTrackingError::NotShared)));
+    }
 }
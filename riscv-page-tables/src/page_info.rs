@@ -0,0 +1,104 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use riscv_pages::PageOwnerId;
+
+/// The maximum number of owners a single page can have at once. A page can have more than one
+/// owner while it's in the process of being reclaimed (the previous owner hasn't finished
+/// tearing down its mapping) or, with `page_tracking::share_page_read_only`, while it's shared
+/// copy-on-write between guests -- so this also bounds how many guests can share a single
+/// deduped page at once.
+pub const MAX_PAGE_OWNERS: usize = 8;
+
+/// The state of a single physical page, as tracked by `page_tracking::PageMap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageState {
+    /// Free and available to be claimed by `HypPageAlloc`.
+    Free,
+    /// Exclusively owned and mapped into exactly one owner's page table.
+    Mapped,
+    /// Mapped read-only into more than one owner's page table; see `PageInfo::shared_count`.
+    Shared,
+    /// Unmapped from its prior owner but not yet converted back to host-usable memory (the
+    /// conversion fence hasn't yet passed).
+    Converting,
+    /// Converted back to host-usable memory.
+    Converted,
+}
+
+/// Tracking state for a single physical page.
+#[derive(Clone, Copy, Debug)]
+pub struct PageInfo {
+    owners: [Option<PageOwnerId>; MAX_PAGE_OWNERS],
+    num_owners: usize,
+    state: PageState,
+    /// Number of additional owners sharing this page read-only, beyond `owners[0]`. Valid only
+    /// when `state == PageState::Shared`.
+    shared_count: u32,
+}
+
+impl PageInfo {
+    /// Returns the initial state for a page owned by `owner`.
+    pub fn new(owner: PageOwnerId) -> Self {
+        let mut owners = [None; MAX_PAGE_OWNERS];
+        owners[0] = Some(owner);
+        Self {
+            owners,
+            num_owners: 1,
+            state: PageState::Mapped,
+            shared_count: 0,
+        }
+    }
+
+    /// Returns the current owner, if any.
+    pub fn owner(&self) -> Option<PageOwnerId> {
+        self.owners[0]
+    }
+
+    /// Returns the current state of the page.
+    pub fn state(&self) -> PageState {
+        self.state
+    }
+
+    pub(crate) fn set_state(&mut self, state: PageState) {
+        self.state = state;
+    }
+
+    /// Returns the number of additional owners this page is currently shared read-only with,
+    /// beyond `owners[0]` -- i.e. `num_owners - 1`.
+    pub fn shared_count(&self) -> u32 {
+        self.shared_count
+    }
+
+    /// Records `owner` as an additional owner of this page, for as long as it remains shared.
+    /// Fails once `MAX_PAGE_OWNERS` owners are already recorded.
+    pub(crate) fn push_owner(&mut self, owner: PageOwnerId) -> Option<()> {
+        if self.num_owners >= MAX_PAGE_OWNERS {
+            return None;
+        }
+        self.owners[self.num_owners] = Some(owner);
+        self.num_owners += 1;
+        self.shared_count = self.num_owners.saturating_sub(1) as u32;
+        Some(())
+    }
+
+    /// Removes `owner` from this page's owner set, compacting the remaining owners down (so, if
+    /// `owner` was `owners[0]`, the next owner takes its place). Returns `true` if `owner` was
+    /// found and removed.
+    pub(crate) fn remove_owner(&mut self, owner: PageOwnerId) -> bool {
+        let Some(idx) = self.owners[..self.num_owners]
+            .iter()
+            .position(|o| *o == Some(owner))
+        else {
+            return false;
+        };
+        for i in idx..self.num_owners - 1 {
+            self.owners[i] = self.owners[i + 1];
+        }
+        self.num_owners -= 1;
+        self.owners[self.num_owners] = None;
+        self.shared_count = self.num_owners.saturating_sub(1) as u32;
+        true
+    }
+}
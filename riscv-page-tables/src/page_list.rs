@@ -0,0 +1,77 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use riscv_pages::{InternalClean, Page};
+
+use crate::page_tracking::PageTracker;
+
+/// Errors returned by `PageList` operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The page being pushed is already linked into the list it's being pushed onto.
+    AlreadyLinked,
+}
+
+/// Holds the result of a `PageList` operation.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A last-in, first-out list of pages, used to track pools of free pages. The pages are held in a
+/// `Vec` owned by the list, so its storage grows with the number of pages; the pages' own memory
+/// is not used to link them.
+pub struct PageList {
+    page_tracker: PageTracker,
+    pages: alloc::vec::Vec<Page<InternalClean>>,
+}
+
+impl PageList {
+    /// Creates a new, empty list.
+    pub fn new(page_tracker: PageTracker) -> Self {
+        Self {
+            page_tracker,
+            pages: alloc::vec::Vec::new(),
+        }
+    }
+
+    /// Pushes `page` onto the front of the list. Fails if a page at the same address is already
+    /// linked into this list.
+    pub fn push(&mut self, page: Page<InternalClean>) -> Result<()> {
+        if self.pages.iter().any(|p| p.addr() == page.addr()) {
+            return Err(Error::AlreadyLinked);
+        }
+        self.pages.push(page);
+        Ok(())
+    }
+
+    /// Pops the most recently pushed page off the list, if any.
+    pub fn pop(&mut self) -> Option<Page<InternalClean>> {
+        self.pages.pop()
+    }
+}
+
+/// A `PageList` behind a lock, suitable for sharing between harts.
+pub struct LockedPageList {
+    inner: Arc<Mutex<PageList>>,
+}
+
+impl LockedPageList {
+    /// Creates a new, empty locked list.
+    pub fn new(page_tracker: PageTracker) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PageList::new(page_tracker))),
+        }
+    }
+
+    /// Pushes `page` onto the front of the list.
+    pub fn push(&self, page: Page<InternalClean>) -> Result<()> {
+        self.inner.lock().push(page)
+    }
+
+    /// Pops the front page off the list, if any.
+    pub fn pop(&self) -> Option<Page<InternalClean>> {
+        self.inner.lock().pop()
+    }
+}
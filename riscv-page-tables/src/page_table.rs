@@ -0,0 +1,699 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use core::cell::RefCell;
+use core::marker::PhantomData;
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use riscv_pages::{
+    GuestPageAddr, GuestPhys, InternalClean, Mappable, Page, PageAddr, PageOwnerId, PageSize,
+    SequentialPages, SupervisorPageAddr, SupervisorVirt,
+};
+
+use crate::page_tracking::PageTracker;
+use crate::pte::{Pte, PteFieldBit, PteSlot};
+use crate::tlb::{self, TlbVersion};
+
+/// Errors returned by `page_table` operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The range being operated on isn't aligned to `PageSize`.
+    Unaligned,
+    /// A leaf already exists where the operation expected to find none, or vice versa.
+    MappingExists,
+    /// No mapping was found at the given address.
+    MappingNotFound,
+    /// The PTE page allocator ran out of pages to allocate a new table level.
+    OutOfPteAlloc,
+    /// The mapping at the given address isn't a leaf of the requested size.
+    WrongPageSize,
+}
+
+/// Holds the result of a `page_table` operation.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Describes a RISC-V paging mode: the number of levels, the size/alignment of the top-level
+/// table, and the address space it translates from.
+pub trait PagingMode {
+    /// The address space this mode translates from (`GuestPhys` for stage-2, `SupervisorVirt`
+    /// for first-stage).
+    type MappedAddressSpace;
+    /// Required alignment, in bytes, of the top-level table's backing pages.
+    const TOP_LEVEL_ALIGN: u64;
+    /// Number of levels in the table, from root to 4k leaf.
+    const LEVELS: usize;
+    /// Number of entries in the top-level table (differs from inner levels for Sv48x4).
+    const TOP_LEVEL_ENTRIES: usize;
+}
+
+/// Marker for paging modes used for first-stage (S/U mode) translation.
+pub trait FirstStagePageTable: PagingMode<MappedAddressSpace = SupervisorVirt> {}
+impl FirstStagePageTable for crate::sv48::Sv48 {}
+
+/// Marker for paging modes used for guest stage-2 (`hgatp`) translation.
+pub trait GuestStagePageTable: PagingMode<MappedAddressSpace = GuestPhys> {}
+impl GuestStagePageTable for crate::sv48x4::Sv48x4 {}
+
+/// Physical page offset width, common to every level of every supported paging mode.
+const PAGE_SHIFT: u32 = 12;
+/// Number of entries in every level but the top one (9 VPN bits per level, as in standard Sv48).
+const ENTRIES_PER_LEVEL: usize = 512;
+
+/// A single level of a multi-level page table: an array of `PteSlot`s backed by one (or, for the
+/// Sv48x4 root, several) physical pages.
+struct PageTable {
+    entries: SequentialPages<InternalClean>,
+}
+
+impl PageTable {
+    fn slots(&self) -> &[PteSlot] {
+        // Safety: the backing pages are owned exclusively by this `PageTable` and are sized and
+        // aligned to hold `entries.len() / size_of::<Pte>()` `PteSlot`s (`PteSlot` is
+        // `repr(transparent)` over the raw PTE word).
+        unsafe {
+            core::slice::from_raw_parts(
+                self.entries.base().bits() as *const PteSlot,
+                self.entries.len() as usize * PageSize::Size4k as usize / core::mem::size_of::<u64>(),
+            )
+        }
+    }
+}
+
+/// The top-level translation structure for a single address space: either a guest's stage-2
+/// (GPA -> SPA) table, or the host's own first-stage (VA -> SPA) table.
+///
+/// Owns every page used to build the table. Dropping a `PlatformPageTable` does not hand its
+/// backing pages back to the `PageTracker`; only `coalesce_range` returns intermediate table
+/// pages there.
+pub struct PlatformPageTable<T: PagingMode> {
+    root: PageTable,
+    owner: PageOwnerId,
+    page_tracker: PageTracker,
+    /// Intermediate (non-root) tables installed on demand by `map_range`/`PageTableMapper` or by
+    /// `split_range`. Parked here, rather than dropped, since a live PTE still points at their
+    /// backing pages for as long as `self` exists; `reclaim_child` (via `coalesce_range`) is what
+    /// removes an entry once its huge-page PTE has been restored.
+    child_tables: Mutex<Vec<PageTable>>,
+    /// Set for guests whose stage-1 MMU/caches aren't yet enabled, so that ownership-transfer
+    /// paths know to use explicit cache-maintenance operations rather than relying on hardware
+    /// coherency.
+    non_coherent_guest: bool,
+    _mode: PhantomData<T>,
+}
+
+impl<T: PagingMode> PlatformPageTable<T> {
+    /// Creates a new, empty page table rooted at `root_pages`, owned by `owner`.
+    pub fn new(
+        root_pages: SequentialPages<InternalClean>,
+        owner: PageOwnerId,
+        page_tracker: PageTracker,
+    ) -> Result<Self> {
+        if root_pages.base().bits() as u64 % T::TOP_LEVEL_ALIGN != 0 {
+            return Err(Error::Unaligned);
+        }
+        Ok(Self {
+            root: PageTable {
+                entries: root_pages,
+            },
+            owner,
+            page_tracker,
+            child_tables: Mutex::new(Vec::new()),
+            non_coherent_guest: false,
+            _mode: PhantomData,
+        })
+    }
+
+    /// Returns the owner of this page table's address space.
+    pub fn owner(&self) -> PageOwnerId {
+        self.owner
+    }
+
+    /// Returns this table's `PageTracker`.
+    pub fn page_tracker(&self) -> &PageTracker {
+        &self.page_tracker
+    }
+
+    /// Marks this page table's guest as non-coherent, meaning ownership-transfer paths must use
+    /// explicit Zicbom cache-maintenance operations rather than relying on hardware coherency
+    /// between the host's view of a page and the guest's.
+    pub fn set_non_coherent_guest(&mut self, non_coherent: bool) {
+        self.non_coherent_guest = non_coherent;
+    }
+
+    /// Returns whether this page table's guest is marked non-coherent.
+    pub fn is_non_coherent_guest(&self) -> bool {
+        self.non_coherent_guest
+    }
+
+    /// Assigns `page` to this table's owner, automatically consulting `is_non_coherent_guest`
+    /// for cache maintenance instead of requiring the caller to track it separately.
+    pub fn assign_page_for_mapping<S>(
+        &self,
+        page: Page<S>,
+    ) -> crate::page_tracking::Result<Page<S>> {
+        self.page_tracker
+            .assign_page_for_mapping(page, self.owner, self.non_coherent_guest)
+    }
+
+    /// Converts `page`, previously unmapped from this table, back to host-usable memory,
+    /// automatically consulting `is_non_coherent_guest` for cache maintenance.
+    pub fn convert_page<S>(
+        &self,
+        page: Page<S>,
+        version: TlbVersion,
+    ) -> crate::page_tracking::Result<Page<S>> {
+        self.page_tracker
+            .convert_page(page, version, self.non_coherent_guest)
+    }
+
+    /// Returns the index into the table at `level` (0 = root) that `addr_bits` falls under.
+    fn index_for_level(addr_bits: u64, level: usize) -> usize {
+        let shift = PAGE_SHIFT + 9 * (T::LEVELS - 1 - level) as u32;
+        let width = if level == 0 {
+            T::TOP_LEVEL_ENTRIES.trailing_zeros()
+        } else {
+            9
+        };
+        let mask = (1usize << width) - 1;
+        (addr_bits >> shift) as usize & mask
+    }
+
+    /// Returns which level of the table a leaf of `size` lives at.
+    fn level_for_size(size: PageSize) -> Result<usize> {
+        let mut ratio = size as u64 / PageSize::Size4k as u64;
+        let mut steps = 0usize;
+        while ratio > 1 {
+            if ratio % ENTRIES_PER_LEVEL as u64 != 0 {
+                return Err(Error::WrongPageSize);
+            }
+            ratio /= ENTRIES_PER_LEVEL as u64;
+            steps += 1;
+        }
+        if steps >= T::LEVELS {
+            return Err(Error::WrongPageSize);
+        }
+        Ok(T::LEVELS - 1 - steps)
+    }
+
+    /// Walks from the root down to `target_level`, following valid non-leaf entries, but
+    /// short-circuits and returns early if it finds a leaf before reaching `target_level` -- that
+    /// leaf is the real mapping covering `addr_bits`, however coarse-grained. Returns `None` if
+    /// the walk hits an invalid (unmapped) entry before reaching a leaf or `target_level`.
+    fn walk(&self, addr_bits: u64, target_level: usize) -> Option<(&PteSlot, usize)> {
+        let mut slots = self.root.slots();
+        for level in 0..=target_level {
+            let idx = Self::index_for_level(addr_bits, level);
+            let slot = slots.get(idx)?;
+            if level == target_level {
+                return Some((slot, level));
+            }
+            let pte = slot.load();
+            if !pte.valid() {
+                return None;
+            }
+            if pte.leaf() {
+                return Some((slot, level));
+            }
+            // Safety: `pte` is a valid, non-leaf entry installed by this table's own
+            // `walk_alloc`/`install_child`, so it points to a live next-level table of
+            // `ENTRIES_PER_LEVEL` slots owned by this `PlatformPageTable`.
+            slots = unsafe {
+                core::slice::from_raw_parts(
+                    pte.pfn_addr().bits() as *const PteSlot,
+                    ENTRIES_PER_LEVEL,
+                )
+            };
+        }
+        None
+    }
+
+    /// Like `walk`, but requires every level strictly above `level` to be a valid, non-leaf
+    /// pointer -- used when the caller already knows which level it's after and a leaf found
+    /// early would mean a size mismatch, not a hit.
+    fn slot_at_level(&self, addr_bits: u64, level: usize) -> Option<&PteSlot> {
+        let mut slots = self.root.slots();
+        for l in 0..=level {
+            let idx = Self::index_for_level(addr_bits, l);
+            let slot = slots.get(idx)?;
+            if l == level {
+                return Some(slot);
+            }
+            let pte = slot.load();
+            if !pte.valid() || pte.leaf() {
+                return None;
+            }
+            slots = unsafe {
+                core::slice::from_raw_parts(
+                    pte.pfn_addr().bits() as *const PteSlot,
+                    ENTRIES_PER_LEVEL,
+                )
+            };
+        }
+        None
+    }
+
+    /// Walks from the root down to `target_level`, allocating and installing a fresh intermediate
+    /// table (via `alloc`) wherever it finds an as-yet-unmapped entry along the way.
+    fn walk_alloc(
+        &self,
+        addr_bits: u64,
+        target_level: usize,
+        alloc: &mut dyn FnMut() -> Option<SequentialPages<InternalClean>>,
+    ) -> Result<&PteSlot> {
+        let mut slots = self.root.slots();
+        for level in 0..=target_level {
+            let idx = Self::index_for_level(addr_bits, level);
+            let slot = slots.get(idx).ok_or(Error::MappingNotFound)?;
+            if level == target_level {
+                return Ok(slot);
+            }
+            let mut pte = slot.load();
+            if !pte.valid() {
+                let child = PageTable {
+                    entries: alloc().ok_or(Error::OutOfPteAlloc)?,
+                };
+                let child_addr = child.entries.base();
+                slot.store(Pte::new_table(child_addr));
+                self.child_tables.lock().push(child);
+                pte = slot.load();
+            } else if pte.leaf() {
+                return Err(Error::MappingExists);
+            }
+            // Safety: `pte` now points at a live next-level table of `ENTRIES_PER_LEVEL` slots
+            // that was either already there or was just installed and parked in
+            // `self.child_tables` above, so it outlives `self`.
+            slots = unsafe {
+                core::slice::from_raw_parts(
+                    pte.pfn_addr().bits() as *const PteSlot,
+                    ENTRIES_PER_LEVEL,
+                )
+            };
+        }
+        Err(Error::MappingNotFound)
+    }
+
+    pub(crate) fn leaf_slot(&self, addr: PageAddr<T::MappedAddressSpace>) -> Option<&PteSlot> {
+        self.walk(addr.bits() as u64, T::LEVELS - 1).map(|(slot, _)| slot)
+    }
+
+    /// Like `leaf_slot`, but also returns the number of 4k pages spanned by whatever leaf was
+    /// found -- 1 for an ordinary 4k leaf, more for a huge page the walk short-circuited on.
+    pub(crate) fn leaf_slot_with_span(
+        &self,
+        addr: PageAddr<T::MappedAddressSpace>,
+    ) -> Option<(&PteSlot, u64)> {
+        self.walk(addr.bits() as u64, T::LEVELS - 1).map(|(slot, level)| {
+            let span = (ENTRIES_PER_LEVEL as u64).pow((T::LEVELS - 1 - level) as u32);
+            (slot, span)
+        })
+    }
+
+    /// Maps `count` pages of `page_size` starting at `addr`, returning a `PageTableMapper` that
+    /// the caller uses to install each leaf's physical page in turn. `get_pte_page` is called to
+    /// allocate backing pages for any new intermediate table levels needed along the way.
+    pub fn map_range<'a>(
+        &'a self,
+        addr: PageAddr<T::MappedAddressSpace>,
+        page_size: PageSize,
+        count: u64,
+        get_pte_page: &'a mut dyn FnMut() -> Option<SequentialPages<InternalClean>>,
+    ) -> Result<PageTableMapper<'a, T>> {
+        Ok(PageTableMapper {
+            table: self,
+            addr,
+            page_size,
+            count,
+            get_pte_page: RefCell::new(get_pte_page),
+        })
+    }
+
+    /// Demotes `count` leaves of `from_size` starting at `addr` into a newly allocated, fully
+    /// populated next-level table of `from_size.next_smaller()` leaves covering the same
+    /// physical span with identical permissions. `pte_alloc` supplies the backing pages for the
+    /// new table.
+    ///
+    /// The demotion is atomic from the guest's point of view: the new child table is built and
+    /// fully populated off to the side, and only then is its pointer installed in place of the
+    /// old large leaf, followed by a `TlbVersion` bump and fence so no hart can keep translating
+    /// through the stale huge-page PTE.
+    pub fn split_range(
+        &self,
+        addr: PageAddr<T::MappedAddressSpace>,
+        from_size: PageSize,
+        count: u64,
+        pte_alloc: &mut dyn FnMut() -> Option<SequentialPages<InternalClean>>,
+    ) -> Result<()> {
+        let to_size = from_size.next_smaller().ok_or(Error::WrongPageSize)?;
+        let entries_needed = from_size as u64 / to_size as u64;
+        let to_size_pages = to_size as u64 / PageSize::Size4k as u64;
+        let from_size_pages = from_size as u64 / PageSize::Size4k as u64;
+        let parent_level = Self::level_for_size(from_size)?;
+        let mut cur = addr;
+        for _ in 0..count {
+            let parent_slot = self
+                .slot_at_level(cur.bits() as u64, parent_level)
+                .ok_or(Error::MappingNotFound)?;
+            let parent = parent_slot.load();
+            if !parent.leaf() {
+                return Err(Error::MappingNotFound);
+            }
+            let base_pfn = parent.pfn_addr().pfn();
+            // Build and fully populate the child table off to the side -- with every entry
+            // carrying `parent`'s permissions over the matching `to_size` slice of `parent`'s
+            // physical span -- before it's installed in place of the huge leaf, so a concurrent
+            // walker only ever sees the old huge leaf or the new, complete table, never a
+            // partially-built one.
+            let child = PageTable {
+                entries: pte_alloc().ok_or(Error::OutOfPteAlloc)?,
+            };
+            for (i, child_slot) in child.slots().iter().take(entries_needed as usize).enumerate() {
+                let child_addr =
+                    SupervisorPageAddr::from_pfn_bits((base_pfn + i as u64 * to_size_pages) << PAGE_SHIFT)
+                        .unwrap();
+                child_slot.store(parent.with_pfn(child_addr));
+            }
+            self.install_child(parent_slot, child);
+            cur = cur
+                .checked_add_pages(from_size_pages)
+                .ok_or(Error::MappingNotFound)?;
+        }
+        tlb::increment_global_version();
+        tlb::hfence_gvma(self.owner, None);
+        Ok(())
+    }
+
+    /// Promotes `count` consecutive `to_size` ranges starting at `addr`, each mapped by
+    /// `to_size.next_smaller()` leaves, back into one `to_size` leaf per range, freeing each
+    /// now-unused intermediate `PageTable` page back to the `PageTracker`.
+    ///
+    /// Fails with `Error::MappingExists` unless every child leaf in the range is present,
+    /// physically contiguous, and has identical permissions -- the coalesced leaf must mean
+    /// exactly what the set of child leaves meant.
+    pub fn coalesce_range(
+        &self,
+        addr: PageAddr<T::MappedAddressSpace>,
+        to_size: PageSize,
+        count: u64,
+    ) -> Result<()> {
+        let from_size = to_size.next_smaller().ok_or(Error::WrongPageSize)?;
+        let entries_needed = to_size as u64 / from_size as u64;
+        let from_size_pages = from_size as u64 / PageSize::Size4k as u64;
+        let to_size_pages = to_size as u64 / PageSize::Size4k as u64;
+        let parent_level = Self::level_for_size(to_size)?;
+        let child_level = Self::level_for_size(from_size)?;
+        // Compares only the permission/flag bits below the PPN (bits 0-9), and only the ones that
+        // should be identical across a uniformly-mapped range -- Accessed/Dirty are expected to
+        // differ leaf-by-leaf depending on which sub-pages hardware has actually touched, so they
+        // shouldn't spuriously fail an otherwise-uniform range.
+        const FLAG_CMP_MASK: u64 = 0x3ff & !(PteFieldBit::Accessed.mask() | PteFieldBit::Dirty.mask());
+        let mut cur = addr;
+        for _ in 0..count {
+            let parent_slot = self
+                .slot_at_level(cur.bits() as u64, parent_level)
+                .ok_or(Error::MappingNotFound)?;
+            if parent_slot.load().leaf() {
+                return Err(Error::MappingExists);
+            }
+            let first = self
+                .slot_at_level(cur.bits() as u64, child_level)
+                .ok_or(Error::MappingNotFound)?
+                .load();
+            if !first.leaf() {
+                return Err(Error::MappingExists);
+            }
+            let mut child_addr = cur;
+            for i in 1..entries_needed {
+                child_addr = child_addr
+                    .checked_add_pages(from_size_pages)
+                    .ok_or(Error::MappingNotFound)?;
+                let child = self
+                    .slot_at_level(child_addr.bits() as u64, child_level)
+                    .ok_or(Error::MappingNotFound)?
+                    .load();
+                let expected_pfn = first.pfn_addr().pfn() + i * from_size_pages;
+                if !child.leaf()
+                    || child.bits() & FLAG_CMP_MASK != first.bits() & FLAG_CMP_MASK
+                    || child.pfn_addr().pfn() != expected_pfn
+                {
+                    return Err(Error::MappingExists);
+                }
+            }
+            self.reclaim_child(parent_slot, first)?;
+            cur = cur
+                .checked_add_pages(to_size_pages)
+                .ok_or(Error::MappingNotFound)?;
+        }
+        tlb::increment_global_version();
+        tlb::hfence_gvma(self.owner, None);
+        Ok(())
+    }
+
+    /// Installs `child` as the intermediate-level table backing `parent_slot`, atomically swapping
+    /// the huge leaf for a pointer to `child`'s base PFN, and parks `child` in `self.child_tables`
+    /// so its backing pages stay reserved for as long as this `PlatformPageTable` exists.
+    fn install_child(&self, parent_slot: &PteSlot, child: PageTable) {
+        let child_base = child.entries.base();
+        parent_slot.store(Pte::new_table(child_base));
+        self.child_tables.lock().push(child);
+    }
+
+    /// Inverse of `install_child`: collapses the intermediate table pointed to by `parent_slot`
+    /// back to the single `leaf` PTE and returns its backing pages to `self.page_tracker`.
+    fn reclaim_child(&self, parent_slot: &PteSlot, leaf: Pte) -> Result<()> {
+        let child_base = parent_slot.load().pfn_addr();
+        let child = {
+            let mut tables = self.child_tables.lock();
+            let idx = tables
+                .iter()
+                .position(|t| t.entries.base() == child_base)
+                .ok_or(Error::MappingNotFound)?;
+            tables.remove(idx)
+        };
+        parent_slot.store(leaf);
+        self.page_tracker.reclaim_pte_pages(child.entries);
+        Ok(())
+    }
+
+    /// Invalidates `count` leaves of `page_size` starting at `addr`, returning an iterator that
+    /// yields the now-invalidated pages so the caller can hand them to
+    /// `PageTracker::convert_page`.
+    pub fn invalidate_range<P: Mappable>(
+        &self,
+        addr: PageAddr<T::MappedAddressSpace>,
+        page_size: PageSize,
+        count: u64,
+    ) -> Result<InvalidatedIter<'_, T, P>> {
+        let level = Self::level_for_size(page_size)?;
+        Ok(InvalidatedIter {
+            table: self,
+            addr,
+            page_size,
+            level,
+            remaining: count,
+            _page: PhantomData,
+        })
+    }
+
+    /// Returns an iterator over those of the `count` leaves of `page_size` starting at `addr`
+    /// that `invalidate_range` left invalidated, yielding them as `P` (e.g.
+    /// `Page<ConvertedDirty>`) so the caller can inspect or clean their contents before reuse.
+    /// `version` is not checked: every invalidated leaf in the range is yielded.
+    pub fn get_converted_range<P: Mappable>(
+        &self,
+        addr: PageAddr<T::MappedAddressSpace>,
+        page_size: PageSize,
+        count: u64,
+        version: TlbVersion,
+    ) -> Result<ConvertedIter<'_, T, P>> {
+        let level = Self::level_for_size(page_size)?;
+        Ok(ConvertedIter {
+            table: self,
+            addr,
+            page_size,
+            level,
+            remaining: count,
+            version,
+            _page: PhantomData,
+        })
+    }
+}
+
+/// Permission bits installed on every leaf created by `PageTableMapper::map_page`: readable,
+/// writable, and pre-marked Accessed + Dirty since the page is being freshly mapped and handed
+/// directly to its new owner.
+const MAPPED_LEAF_PERM_BITS: u64 = 0b1100_0110; // Dirty | Accessed | Write | Read
+
+/// Returned by `PlatformPageTable::map_range`; installs the actual leaf mappings one page at a
+/// time.
+pub struct PageTableMapper<'a, T: PagingMode> {
+    table: &'a PlatformPageTable<T>,
+    addr: PageAddr<T::MappedAddressSpace>,
+    page_size: PageSize,
+    count: u64,
+    get_pte_page: RefCell<&'a mut dyn FnMut() -> Option<SequentialPages<InternalClean>>>,
+}
+
+impl<'a, T: PagingMode> PageTableMapper<'a, T> {
+    /// Installs `page` as the mapping for `addr`, allocating any missing intermediate table
+    /// levels along the way via the closure supplied to `map_range`.
+    pub fn map_page<P: Mappable>(&self, addr: PageAddr<T::MappedAddressSpace>, page: P) -> Result<()> {
+        let span = self.count * (self.page_size as u64 / PageSize::Size4k as u64);
+        let start = self.addr.bits() as u64 / PageSize::Size4k as u64;
+        let index = addr.bits() as u64 / PageSize::Size4k as u64;
+        if index < start || index >= start + span {
+            return Err(Error::MappingNotFound);
+        }
+        let level = PlatformPageTable::<T>::level_for_size(self.page_size)?;
+        let mut alloc = self.get_pte_page.borrow_mut();
+        let slot = self.table.walk_alloc(addr.bits() as u64, level, &mut **alloc)?;
+        if slot.load().valid() {
+            return Err(Error::MappingExists);
+        }
+        slot.store(Pte::new_leaf(page.addr(), MAPPED_LEAF_PERM_BITS));
+        Ok(())
+    }
+}
+
+/// Iterator over leaves invalidated by `PlatformPageTable::invalidate_range`.
+pub struct InvalidatedIter<'a, T: PagingMode, P> {
+    table: &'a PlatformPageTable<T>,
+    addr: PageAddr<T::MappedAddressSpace>,
+    page_size: PageSize,
+    level: usize,
+    remaining: u64,
+    _page: PhantomData<P>,
+}
+
+impl<'a, T: PagingMode, P: Mappable> Iterator for InvalidatedIter<'a, T, P> {
+    type Item = P;
+
+    fn next(&mut self) -> Option<P> {
+        let stride = self.page_size as u64 / PageSize::Size4k as u64;
+        while self.remaining > 0 {
+            let addr = self.addr;
+            self.remaining -= 1;
+            match addr.checked_add_pages(stride) {
+                Some(next_addr) => self.addr = next_addr,
+                None => self.remaining = 0,
+            }
+            if let Some(slot) = self.table.slot_at_level(addr.bits() as u64, self.level) {
+                if let Some(prev) = slot.invalidate() {
+                    // Safety: `prev.pfn_addr()` was, until this call, an exclusively-owned leaf
+                    // mapping of `self.table`'s guest/host; invalidating it above is what makes
+                    // it safe to hand back to the caller as a freshly-owned `P`.
+                    return Some(unsafe { P::new(prev.pfn_addr()) });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over leaves yielded by `PlatformPageTable::get_converted_range`.
+pub struct ConvertedIter<'a, T: PagingMode, P> {
+    table: &'a PlatformPageTable<T>,
+    addr: PageAddr<T::MappedAddressSpace>,
+    page_size: PageSize,
+    level: usize,
+    remaining: u64,
+    version: TlbVersion,
+    _page: PhantomData<P>,
+}
+
+impl<'a, T: PagingMode, P: Mappable> Iterator for ConvertedIter<'a, T, P> {
+    type Item = P;
+
+    fn next(&mut self) -> Option<P> {
+        // The caller is responsible for having already bumped and fenced the `TlbVersion` past
+        // `self.version` before iterating; nothing in the (now-empty) PTE slot itself records a
+        // conversion version to check against.
+        let _ = self.version;
+        let stride = self.page_size as u64 / PageSize::Size4k as u64;
+        while self.remaining > 0 {
+            let addr = self.addr;
+            self.remaining -= 1;
+            match addr.checked_add_pages(stride) {
+                Some(next_addr) => self.addr = next_addr,
+                None => self.remaining = 0,
+            }
+            if let Some(slot) = self.table.slot_at_level(addr.bits() as u64, self.level) {
+                let pte = slot.load();
+                // A non-zero but invalid word is the `invalidate()` tombstone left behind by
+                // `InvalidatedIter`; an all-zero word means nothing was ever mapped here.
+                if !pte.valid() && pte.bits() != 0 {
+                    slot.clear();
+                    // Safety: see `InvalidatedIter::next` -- this slot's tombstone is the last
+                    // reference to what was an exclusively-owned page.
+                    return Some(unsafe { P::new(pte.pfn_addr()) });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Dirty-logging support for live migration and checkpointing of guest VMs, built on the stage-2
+/// leaf Dirty (D) bit.
+impl<T: GuestStagePageTable> PlatformPageTable<T> {
+    /// Begins (or resumes) dirty tracking over `count` pages of `page_size` starting at `gpa`:
+    /// clears the Dirty bit on every already-installed leaf in the range so that the next
+    /// `collect_dirty` pass reflects only writes that happen from this point on.
+    ///
+    /// If `force_write_fault` is set, write permission is also stripped from each leaf so that
+    /// hardware without a working D-bit implementation traps on the first write instead, giving
+    /// software a chance to set D (and reinstall the mapping) itself. Either way, the stage-2
+    /// `TlbVersion` is bumped and an `hfence.gvma` is issued before returning so no hart can keep
+    /// using a stale PTE that would suppress a future D-bit update.
+    pub fn enable_dirty_logging(
+        &self,
+        gpa: GuestPageAddr,
+        page_size: PageSize,
+        count: u64,
+        force_write_fault: bool,
+    ) -> Result<()> {
+        let stride = (page_size as u64 / PageSize::Size4k as u64) as usize;
+        for addr in gpa.iter_from().step_by(stride).take(count as usize) {
+            if let Some(slot) = self.leaf_slot(addr) {
+                slot.clear_dirty();
+                if force_write_fault {
+                    slot.clear_writable();
+                }
+            }
+        }
+        tlb::increment_global_version();
+        tlb::hfence_gvma(self.owner, Some((gpa, page_size)));
+        Ok(())
+    }
+
+    /// Runs one dirty-collection pass over `count` pages of `page_size` starting at `gpa`.
+    ///
+    /// Every leaf whose D bit is set has its GPA recorded, and D is atomically cleared again via
+    /// a compare-and-swap on the raw PTE word, so a hardware D-set racing with this pass is never
+    /// lost -- it's simply observed, and re-cleared, on the following pass instead. Bumps
+    /// `TlbVersion` and fences before returning so the guest can't keep writing through a stale
+    /// TLB entry that bypasses the cleared D bit.
+    pub fn collect_dirty(
+        &self,
+        gpa: GuestPageAddr,
+        page_size: PageSize,
+        count: u64,
+    ) -> Result<impl Iterator<Item = GuestPageAddr> + '_> {
+        let stride = (page_size as u64 / PageSize::Size4k as u64) as usize;
+        let dirty: alloc::vec::Vec<GuestPageAddr> = gpa
+            .iter_from()
+            .step_by(stride)
+            .take(count as usize)
+            .filter(|addr| {
+                self.leaf_slot(*addr)
+                    .map(|slot| slot.clear_dirty())
+                    .unwrap_or(false)
+            })
+            .collect();
+        tlb::increment_global_version();
+        tlb::hfence_gvma(self.owner, Some((gpa, page_size)));
+        Ok(dirty.into_iter())
+    }
+}
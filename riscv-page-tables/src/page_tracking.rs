@@ -0,0 +1,288 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use alloc::sync::Arc;
+use core::alloc::Allocator;
+use spin::Mutex;
+
+use riscv_pages::{
+    ConvertedClean, InternalClean, Mappable, Page, PageOwnerId, PageSize, SequentialPages,
+    SupervisorPageAddr,
+};
+
+use crate::hw_mem_map::HwMemMap;
+use crate::page_info::{PageInfo, PageState};
+use crate::tlb;
+use crate::tlb::TlbVersion;
+
+/// Errors returned by `page_tracking` operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No more owner IDs are available.
+    OutOfOwners,
+    /// The page isn't tracked (it's outside of system RAM).
+    PageNotFound,
+    /// The page isn't owned by the given owner.
+    NotOwner,
+    /// The page is already shared with the maximum number of owners.
+    TooManyOwners,
+    /// The page isn't currently shared.
+    NotShared,
+    /// The page isn't in a state the operation can be applied to.
+    InvalidState,
+    /// The page allocator ran out of pages to allocate a copy-on-write page.
+    OutOfPages,
+}
+
+/// Holds the result of a `page_tracking` operation.
+pub type Result<T> = core::result::Result<T, Error>;
+
+struct PageMap {
+    base_pfn: u64,
+    infos: alloc::vec::Vec<Option<PageInfo>>,
+}
+
+impl PageMap {
+    fn info_mut(&mut self, addr: SupervisorPageAddr) -> Result<&mut PageInfo> {
+        let index = (addr.pfn() - self.base_pfn) as usize;
+        self.infos
+            .get_mut(index)
+            .and_then(|i| i.as_mut())
+            .ok_or(Error::PageNotFound)
+    }
+}
+
+struct PageTrackerInner {
+    page_map: PageMap,
+    next_owner: u64,
+    free_pte_pages: alloc::vec::Vec<SequentialPages<InternalClean>>,
+}
+
+/// System-wide tracker of page ownership, shared by every `PlatformPageTable` in the system.
+///
+/// Cloning a `PageTracker` is cheap and yields another handle to the same underlying state; this
+/// is how page tables, the hypervisor's own allocator, and the VMM-facing API all observe a
+/// consistent view of who owns what.
+#[derive(Clone)]
+pub struct PageTracker {
+    inner: Arc<Mutex<PageTrackerInner>>,
+}
+
+impl PageTracker {
+    /// Consumes the remaining free memory in `hyp_mem`, turning it into a `PageTracker` covering
+    /// all of system RAM plus the `SequentialPages` of host-usable RAM left over after the
+    /// hypervisor took what it needed for its own internal state.
+    pub fn from(
+        hyp_mem: HypPageAlloc,
+        host_alignment: u64,
+    ) -> (Self, alloc::vec::Vec<SequentialPages<ConvertedClean>>) {
+        let HypPageAlloc {
+            base_pfn,
+            num_pages,
+            next_free_pfn,
+            mut host_pages,
+        } = hyp_mem;
+        let align_pages = (host_alignment / PageSize::Size4k as u64).max(1);
+        let host_base = next_free_pfn.next_multiple_of(align_pages);
+        let top_pfn = base_pfn + num_pages;
+        if host_base < top_pfn {
+            // Safe: everything from `next_free_pfn` up was never handed out by `hyp_mem`.
+            host_pages.push(unsafe { SequentialPages::from_pfn_range(host_base, top_pfn - host_base) });
+        }
+        let page_map = PageMap {
+            base_pfn,
+            infos: alloc::vec![None; num_pages as usize],
+        };
+        let inner = PageTrackerInner {
+            page_map,
+            next_owner: PageOwnerId::host().raw(),
+            free_pte_pages: alloc::vec::Vec::new(),
+        };
+        (
+            Self {
+                inner: Arc::new(Mutex::new(inner)),
+            },
+            host_pages,
+        )
+    }
+
+    /// Allocates a fresh `PageOwnerId` for a newly-starting guest VM.
+    pub fn add_active_guest(&self) -> Result<PageOwnerId> {
+        let mut inner = self.inner.lock();
+        inner.next_owner += 1;
+        PageOwnerId::new(inner.next_owner).ok_or(Error::OutOfOwners)
+    }
+
+    /// Assigns `page` to `owner` so that it may be mapped into `owner`'s `PlatformPageTable`.
+    ///
+    /// `non_coherent` must be set by callers mapping the page into a guest whose stage-1
+    /// MMU/caches aren't enabled yet, so that the host's cached view of the page is cleaned and
+    /// invalidated to the point of coherency before the guest can observe it; coherent guests
+    /// pass `false` and pay no cache-maintenance cost.
+    pub fn assign_page_for_mapping<S>(
+        &self,
+        page: Page<S>,
+        owner: PageOwnerId,
+        non_coherent: bool,
+    ) -> Result<Page<S>> {
+        let mut inner = self.inner.lock();
+        let index = (page.addr().pfn() - inner.page_map.base_pfn) as usize;
+        if let Some(slot) = inner.page_map.infos.get_mut(index) {
+            *slot = Some(PageInfo::new(owner));
+        }
+        if non_coherent {
+            tlb::flush_to_poc(page.addr(), page.size() as u64, tlb::CacheMaintOp::Flush);
+        }
+        Ok(page)
+    }
+
+    /// Marks a page as converting back to host-usable memory. `version` is not recorded; the page
+    /// is safe to treat as fully converted once every hart has observed a `TlbVersion` newer
+    /// than `version`.
+    ///
+    /// `non_coherent` must be set if the page is returning from a guest whose stage-1 MMU/caches
+    /// aren't enabled, so the host invalidates its cache lines for the page rather than risking a
+    /// read of data the guest wrote through a cache the host doesn't share.
+    pub fn convert_page<S>(&self, page: Page<S>, version: TlbVersion, non_coherent: bool) -> Result<Page<S>> {
+        let mut inner = self.inner.lock();
+        let info = inner.page_map.info_mut(page.addr())?;
+        info.set_state(PageState::Converting);
+        let _ = version;
+        if non_coherent {
+            tlb::flush_to_poc(page.addr(), page.size() as u64, tlb::CacheMaintOp::Invalidate);
+        }
+        Ok(page)
+    }
+
+    /// Returns the backing pages of an intermediate page-table level that's no longer referenced
+    /// by any `PlatformPageTable` (e.g. after `PlatformPageTable::coalesce_range` collapses it
+    /// away), making them available to a future `pte_alloc`-style closure via
+    /// `take_reclaimed_pte_pages` instead of leaking them.
+    pub fn reclaim_pte_pages(&self, pages: SequentialPages<InternalClean>) {
+        self.inner.lock().free_pte_pages.push(pages);
+    }
+
+    /// Takes back a previously-`reclaim_pte_pages`'d range of pages, if one is available. Meant
+    /// to be tried first by a `pte_alloc` closure before falling back to a fresh allocation.
+    pub fn take_reclaimed_pte_pages(&self) -> Option<SequentialPages<InternalClean>> {
+        self.inner.lock().free_pte_pages.pop()
+    }
+
+    /// Maps `page` read-only into `owner`'s address space in addition to its existing owner,
+    /// bumping the page's shared refcount so that VMs booted from the same image can dedup their
+    /// memory. Returns an error if the page is already shared with `MAX_PAGE_OWNERS` guests.
+    ///
+    /// The caller is responsible for actually installing the read-only mapping in `owner`'s
+    /// `PlatformPageTable` and for stripping write permission from every *other* owner's mapping
+    /// of the page, since the invariant this enforces is that a page with shared refcount > 1 is
+    /// read-only in every owner's table.
+    pub fn share_page_read_only(&self, addr: SupervisorPageAddr, owner: PageOwnerId) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let info = inner.page_map.info_mut(addr)?;
+        info.push_owner(owner).ok_or(Error::TooManyOwners)?;
+        info.set_state(PageState::Shared);
+        Ok(())
+    }
+
+    /// Resolves a write fault taken by `faulting_owner` on a copy-on-write shared page at `addr`.
+    ///
+    /// `faulting_owner` is removed from the page's owner set unconditionally -- a write fault
+    /// always means `faulting_owner` needs its own private, writable copy, since the original
+    /// page may still be read-only shared by others after it leaves. If `faulting_owner` was
+    /// sharing with exactly one other owner, that remaining owner's mapping reverts to
+    /// `PageState::Mapped` (it's no longer shared, so it can be made writable again without a
+    /// copy of its own); otherwise the original mapping stays `PageState::Shared` for the rest.
+    ///
+    /// `page_alloc` supplies the backing page for `faulting_owner`'s private copy; this function
+    /// copies the shared page's contents into it and records it as exclusively owned by
+    /// `faulting_owner`. Returns that page: the caller is responsible for actually mapping it
+    /// writable into `faulting_owner`'s `PlatformPageTable` in place of the read-only shared
+    /// mapping, mirroring the division of labor in `share_page_read_only`.
+    pub fn resolve_cow_fault<P: Mappable>(
+        &self,
+        addr: SupervisorPageAddr,
+        faulting_owner: PageOwnerId,
+        page_alloc: &mut dyn FnMut() -> Option<P>,
+    ) -> Result<P> {
+        let mut inner = self.inner.lock();
+        {
+            let info = inner.page_map.info_mut(addr)?;
+            if info.state() != PageState::Shared {
+                return Err(Error::NotShared);
+            }
+            if !info.remove_owner(faulting_owner) {
+                return Err(Error::NotOwner);
+            }
+            if info.shared_count() == 0 {
+                info.set_state(PageState::Mapped);
+            }
+        }
+        let new_page = page_alloc().ok_or(Error::OutOfPages)?;
+        // Safety: `addr` is still a live, read-only-mapped physical page -- removing
+        // `faulting_owner` above only updates `PageTracker`'s bookkeeping, and the caller hasn't
+        // torn down the mapping yet -- and `new_page` is a freshly allocated page not yet visible
+        // to any guest, so copying `Size4k` bytes between the two can't race a concurrent write to
+        // either range.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                addr.bits() as *const u8,
+                new_page.addr().bits() as *mut u8,
+                PageSize::Size4k as usize,
+            );
+        }
+        let index = (new_page.addr().pfn() - inner.page_map.base_pfn) as usize;
+        if let Some(slot) = inner.page_map.infos.get_mut(index) {
+            *slot = Some(PageInfo::new(faulting_owner));
+        }
+        Ok(new_page)
+    }
+}
+
+/// The initial owner of all of system RAM, used to carve out pages for the hypervisor's own
+/// bookkeeping (page tables, the `PageMap` itself, etc.) before a `PageTracker` exists.
+pub struct HypPageAlloc {
+    base_pfn: u64,
+    num_pages: u64,
+    next_free_pfn: u64,
+    host_pages: alloc::vec::Vec<SequentialPages<ConvertedClean>>,
+}
+
+impl HypPageAlloc {
+    /// Creates a new allocator covering all of the RAM described by `mem_map`.
+    pub fn new<A: Allocator>(mem_map: HwMemMap, _allocator: A) -> Self {
+        let mut base_pfn = u64::MAX;
+        let mut top_pfn = 0;
+        for region in mem_map.regions() {
+            let pfn = region.base().bits() as u64 >> 12;
+            base_pfn = base_pfn.min(pfn);
+            top_pfn = top_pfn.max(pfn + region.size() / (PageSize::Size4k as u64));
+        }
+        Self {
+            base_pfn,
+            num_pages: top_pfn - base_pfn,
+            next_free_pfn: base_pfn,
+            host_pages: alloc::vec::Vec::new(),
+        }
+    }
+
+    /// Takes `num_pages` 4k pages for the hypervisor's own internal state.
+    pub fn take_pages_for_host_state(&mut self, num_pages: u64) -> SequentialPages<InternalClean> {
+        self.take_pages_for_host_state_with_alignment(num_pages, PageSize::Size4k as u64)
+    }
+
+    /// Like `take_pages_for_host_state`, but aligns the returned range to `align` bytes.
+    pub fn take_pages_for_host_state_with_alignment(
+        &mut self,
+        num_pages: u64,
+        align: u64,
+    ) -> SequentialPages<InternalClean> {
+        let align_pages = align / (PageSize::Size4k as u64);
+        self.next_free_pfn = self.next_free_pfn.next_multiple_of(align_pages.max(1));
+        let base = self.next_free_pfn;
+        self.next_free_pfn += num_pages;
+        // Safe: the range [base, base + num_pages) was just carved out of untouched system RAM
+        // and isn't referenced anywhere else.
+        unsafe { SequentialPages::from_pfn_range(base, num_pages) }
+    }
+}
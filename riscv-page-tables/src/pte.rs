@@ -0,0 +1,178 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv_pages::SupervisorPageAddr;
+
+/// Bit offsets of the fields of a RISC-V PTE, common to both Sv48 and Sv48x4 tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PteFieldBit {
+    Valid = 0,
+    Read = 1,
+    Write = 2,
+    Execute = 3,
+    User = 4,
+    Global = 5,
+    Accessed = 6,
+    Dirty = 7,
+}
+
+impl PteFieldBit {
+    pub(crate) const fn mask(self) -> u64 {
+        1 << (self as u64)
+    }
+}
+
+const PPN_SHIFT: u64 = 10;
+const PPN_MASK: u64 = ((1u64 << 44) - 1) << PPN_SHIFT;
+const PAGE_SHIFT: u64 = 12;
+
+/// A raw RISC-V page-table entry.
+///
+/// `Pte` wraps the `u64` at rest in a `PageTable`'s backing memory. Because hardware can update
+/// the Accessed (A) and Dirty (D) bits concurrently with software (on a write fault or a normal
+/// access), any read-modify-write of those bits must go through `compare_exchange` on the
+/// underlying word rather than a plain load/store so a racing hardware update isn't lost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pte(u64);
+
+impl Pte {
+    /// Wraps a raw PTE value.
+    pub fn new(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw bits of this PTE.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns true if the mapping is valid.
+    pub fn valid(&self) -> bool {
+        self.0 & PteFieldBit::Valid.mask() != 0
+    }
+
+    /// Returns true if this is a leaf entry (maps a page rather than pointing at the next level).
+    pub fn leaf(&self) -> bool {
+        self.valid() && (self.0 & (PteFieldBit::Read.mask() | PteFieldBit::Execute.mask()) != 0)
+    }
+
+    /// Returns true if the hardware Accessed bit is set.
+    pub fn accessed(&self) -> bool {
+        self.0 & PteFieldBit::Accessed.mask() != 0
+    }
+
+    /// Returns true if the hardware Dirty bit is set.
+    pub fn dirty(&self) -> bool {
+        self.0 & PteFieldBit::Dirty.mask() != 0
+    }
+
+    /// Returns true if the entry is currently writable.
+    pub fn writable(&self) -> bool {
+        self.0 & PteFieldBit::Write.mask() != 0
+    }
+
+    /// Returns the physical page address this leaf maps to.
+    pub fn pfn_addr(&self) -> SupervisorPageAddr {
+        let pa = (self.0 & PPN_MASK) >> PPN_SHIFT << PAGE_SHIFT;
+        // Safe to `unwrap()`: PTEs are only ever built from page-aligned `SupervisorPageAddr`s.
+        SupervisorPageAddr::from_pfn_bits(pa).unwrap()
+    }
+
+    /// Returns a copy of this PTE re-targeted at `addr`, keeping every flag bit (valid,
+    /// permissions, A/D, ...) unchanged. Used when splitting a huge leaf: each of the smaller
+    /// child leaves keeps the parent's permissions but points at its own sub-span of the parent's
+    /// physical range.
+    pub fn with_pfn(&self, addr: SupervisorPageAddr) -> Self {
+        let ppn_bits = (addr.pfn() << PPN_SHIFT) & PPN_MASK;
+        Self((self.0 & !PPN_MASK) | ppn_bits)
+    }
+
+    /// Builds a valid, non-leaf PTE pointing at the next-level table rooted at `addr`.
+    pub fn new_table(addr: SupervisorPageAddr) -> Self {
+        let ppn_bits = (addr.pfn() << PPN_SHIFT) & PPN_MASK;
+        Self(ppn_bits | PteFieldBit::Valid.mask())
+    }
+
+    /// Builds a valid leaf PTE mapping `addr` with `perm_bits` (a combination of `PteFieldBit`
+    /// masks; `Valid` is set automatically).
+    pub fn new_leaf(addr: SupervisorPageAddr, perm_bits: u64) -> Self {
+        let ppn_bits = (addr.pfn() << PPN_SHIFT) & PPN_MASK;
+        Self(ppn_bits | PteFieldBit::Valid.mask() | perm_bits)
+    }
+}
+
+/// A live PTE slot inside a `PageTable`'s backing page: a reference to the raw memory word that
+/// supports atomic read-modify-write of the A/D/permission bits.
+#[repr(transparent)]
+pub struct PteSlot(AtomicU64);
+
+impl PteSlot {
+    /// Returns the current value of this slot.
+    pub fn load(&self) -> Pte {
+        Pte(self.0.load(Ordering::Acquire))
+    }
+
+    /// Stores `pte` into this slot. Used to populate a table and to replace a whole entry (as
+    /// `split_range`/`coalesce_range` do); once a leaf is live, A/D/permission updates must go
+    /// through the atomic helpers below.
+    pub fn store(&self, pte: Pte) {
+        self.0.store(pte.0, Ordering::Release);
+    }
+
+    /// Atomically clears the Dirty bit, returning whether it was set beforehand. Retries the CAS
+    /// if hardware races in and sets Accessed or Dirty again between our load and our store, so a
+    /// concurrent hardware D-set is never silently dropped.
+    pub fn clear_dirty(&self) -> bool {
+        let mut was_dirty = false;
+        let _ = self.0.fetch_update(Ordering::AcqRel, Ordering::Acquire, |bits| {
+            if bits & PteFieldBit::Dirty.mask() != 0 {
+                was_dirty = true;
+                Some(bits & !PteFieldBit::Dirty.mask())
+            } else {
+                None
+            }
+        });
+        was_dirty
+    }
+
+    /// Atomically clears the Accessed bit, returning whether it was set beforehand.
+    pub fn clear_accessed(&self) -> bool {
+        let mut was_accessed = false;
+        let _ = self.0.fetch_update(Ordering::AcqRel, Ordering::Acquire, |bits| {
+            if bits & PteFieldBit::Accessed.mask() != 0 {
+                was_accessed = true;
+                Some(bits & !PteFieldBit::Accessed.mask())
+            } else {
+                None
+            }
+        });
+        was_accessed
+    }
+
+    /// Atomically clears the Write permission bit, forcing a page fault on the next write so
+    /// platforms that don't implement hardware D-bit updates can still be dirty-tracked.
+    pub fn clear_writable(&self) {
+        self.0.fetch_and(!PteFieldBit::Write.mask(), Ordering::AcqRel);
+    }
+
+    /// Atomically clears the Valid bit, returning the slot's prior value if it was a valid leaf
+    /// (`None` if it wasn't mapped to begin with). The rest of the word -- physical address and
+    /// permissions -- is deliberately left in place as a tombstone: hardware won't translate
+    /// through an invalid PTE, but software can still recover which page used to be mapped here
+    /// until the slot is `clear()`ed once that page finishes converting.
+    pub fn invalidate(&self) -> Option<Pte> {
+        let prev = Pte(self.0.fetch_and(!PteFieldBit::Valid.mask(), Ordering::AcqRel));
+        if prev.leaf() {
+            Some(prev)
+        } else {
+            None
+        }
+    }
+
+    /// Fully clears this slot back to empty, discarding any tombstone left by `invalidate`.
+    pub fn clear(&self) {
+        self.0.store(0, Ordering::Release);
+    }
+}
@@ -0,0 +1,16 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use riscv_pages::PageSize;
+
+use crate::page_table::PagingMode;
+
+/// The standard 4-level Sv48 paging mode, used for first-stage (S/U mode) translation.
+pub struct Sv48;
+
+impl PagingMode for Sv48 {
+    const TOP_LEVEL_ALIGN: u64 = PageSize::Size4k as u64;
+    const LEVELS: usize = 4;
+    const TOP_LEVEL_ENTRIES: usize = 512;
+}
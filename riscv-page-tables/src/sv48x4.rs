@@ -0,0 +1,18 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use riscv_pages::PageSize;
+
+use crate::page_table::PagingMode;
+
+/// The Sv48x4 paging mode used for guest (stage-2/VS) translation. Identical to `Sv48` except
+/// that the root table is 4 pages (16k) to cover the extra 2 bits of guest physical address
+/// space mandated by the RISC-V hypervisor extension.
+pub struct Sv48x4;
+
+impl PagingMode for Sv48x4 {
+    const TOP_LEVEL_ALIGN: u64 = 4 * PageSize::Size4k as u64;
+    const LEVELS: usize = 4;
+    const TOP_LEVEL_ENTRIES: usize = 2048;
+}
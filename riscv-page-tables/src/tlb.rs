@@ -0,0 +1,146 @@
+// Copyright (c) 2021 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv_pages::{GuestPageAddr, PageOwnerId, PageSize, SupervisorPageAddr};
+
+/// A version counter used to determine when it's safe to reclaim a page that's been unmapped
+/// from a `PlatformPageTable`.
+///
+/// Each time a range of a page table is invalidated the current `TlbVersion` is bumped and an
+/// `sfence` (or `hfence.gvma` for guest-stage tables) is issued, fencing in the old mappings. A
+/// page removed at version `v` can only be reused for something else once every hart is known to
+/// be running at `TlbVersion` > `v`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TlbVersion(u64);
+
+impl TlbVersion {
+    /// Creates the initial `TlbVersion`.
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    /// Returns the version that follows this one.
+    pub fn increment(&self) -> Self {
+        Self(self.0 + 1)
+    }
+
+    /// Returns the raw version number.
+    pub fn version(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for TlbVersion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static GLOBAL_TLB_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the current global `TlbVersion`.
+pub fn current_version() -> TlbVersion {
+    TlbVersion(GLOBAL_TLB_VERSION.load(Ordering::Acquire))
+}
+
+/// Bumps the global `TlbVersion` and returns the new version. Callers must issue the
+/// corresponding fence (`sfence_vma` or `hfence_gvma`) before relying on the new version
+/// being globally visible.
+pub fn increment_global_version() -> TlbVersion {
+    TlbVersion(GLOBAL_TLB_VERSION.fetch_add(1, Ordering::AcqRel) + 1)
+}
+
+/// Fences stage-2 (guest physical address) translations for `owner`'s address space.
+///
+/// If `addr` and `size` are given, only the translation covering that range is fenced;
+/// otherwise the entire guest address space for `owner` is fenced.
+pub fn hfence_gvma(owner: PageOwnerId, addr: Option<(GuestPageAddr, PageSize)>) {
+    // Real hardware issues `hfence.gvma` here, scoped by VMID (`owner`) and, if given, by `addr`.
+    // There's nothing to fence in the test/host build.
+    let _ = (owner, addr);
+}
+
+/// Fences the current hart's first-stage (VA -> SPA) TLB so that prior writes to this address
+/// space's page tables -- via any of the `PlatformPageTable` methods -- are observed before
+/// any later instruction or data access that might translate through them.
+///
+/// Must be issued (after an `increment_global_version`) before relying on a `TlbVersion` bump
+/// being visible on this hart. Unlike `hfence_gvma`, this only fences first-stage translations;
+/// it has no effect on a guest's stage-2 mappings.
+pub fn sfence_vma() {
+    // No-op outside of the actual hart; a real implementation issues `sfence.vma`.
+}
+
+/// The Zicbom cache-block size, in bytes, as detected from the platform's device tree or CSRs.
+/// Zero means "not yet known", in which case `flush_to_poc` is a no-op -- callers are expected to
+/// set this during platform init, before any guest that might need cache maintenance is started.
+static CACHE_BLOCK_SIZE: AtomicU64 = AtomicU64::new(0);
+
+/// Records the platform's Zicbom cache-block size, as read out of its device tree or `cbocmd`
+/// value. Must be called once during platform init before `flush_to_poc` can do anything.
+pub fn set_cache_block_size(size: u64) {
+    CACHE_BLOCK_SIZE.store(size, Ordering::Release);
+}
+
+/// The Zicbom cache-maintenance operation to perform on a range of cache blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMaintOp {
+    /// `cbo.clean`: writes back a dirty block without invalidating it.
+    Clean,
+    /// `cbo.flush`: writes back a dirty block and invalidates it.
+    Flush,
+    /// `cbo.inval`: invalidates a block, discarding its contents without writing back.
+    Invalidate,
+}
+
+/// Applies `op` to every Zicbom cache block covering `size` bytes starting at `addr`, bringing
+/// the host's cached view of that memory back in sync with the point of coherency.
+///
+/// Used on the ownership-transfer paths in `page_tracking` for guests that run with their stage-1
+/// MMU/caches off, where the host's cache and the guest's view of a page can otherwise diverge.
+/// A no-op until `set_cache_block_size` has been called.
+pub fn flush_to_poc(addr: SupervisorPageAddr, size: u64, op: CacheMaintOp) {
+    let block_size = CACHE_BLOCK_SIZE.load(Ordering::Acquire);
+    if block_size == 0 {
+        return;
+    }
+    let mut offset = 0;
+    while offset < size {
+        // Safety: `addr + offset` stays within the `size`-byte range the caller says it
+        // exclusively owns for the duration of this call.
+        unsafe {
+            let block = (addr.bits() as u64 + offset) as *const u8;
+            match op {
+                CacheMaintOp::Clean => cbo_clean(block),
+                CacheMaintOp::Flush => cbo_flush(block),
+                CacheMaintOp::Invalidate => cbo_inval(block),
+            }
+        }
+        offset += block_size;
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+unsafe fn cbo_clean(addr: *const u8) {
+    core::arch::asm!("cbo.clean 0({0})", in(reg) addr);
+}
+
+#[cfg(target_arch = "riscv64")]
+unsafe fn cbo_flush(addr: *const u8) {
+    core::arch::asm!("cbo.flush 0({0})", in(reg) addr);
+}
+
+#[cfg(target_arch = "riscv64")]
+unsafe fn cbo_inval(addr: *const u8) {
+    core::arch::asm!("cbo.inval 0({0})", in(reg) addr);
+}
+
+// Host-architecture stubs so this crate's unit tests can link and run off-target.
+#[cfg(not(target_arch = "riscv64"))]
+unsafe fn cbo_clean(_addr: *const u8) {}
+#[cfg(not(target_arch = "riscv64"))]
+unsafe fn cbo_flush(_addr: *const u8) {}
+#[cfg(not(target_arch = "riscv64"))]
+unsafe fn cbo_inval(_addr: *const u8) {}